@@ -4,12 +4,13 @@ use yubikey_piv::{
     certificate::{Certificate, PublicKeyInfo},
     key::{generate as yubikey_generate, AlgorithmId, RetiredSlotId, SlotId},
     policy::{PinPolicy, TouchPolicy},
-    Key, YubiKey,
+    Key, MgmKey, YubiKey,
 };
 
 use crate::{
     error::Error,
-    p256::Recipient,
+    format::Epk,
+    p256, p384,
     util::POLICY_EXTENSION_OID,
     yubikey::{self, Stub},
     PLUGIN_NAME, USABLE_SLOTS,
@@ -17,13 +18,18 @@ use crate::{
 
 const DEFAULT_PIN_POLICY: PinPolicy = PinPolicy::Once;
 const DEFAULT_TOUCH_POLICY: TouchPolicy = TouchPolicy::Always;
+const DEFAULT_ALGORITHM: AlgorithmId = AlgorithmId::EccP256;
 
 pub(crate) struct IdentityBuilder {
     slot: Option<RetiredSlotId>,
     force: bool,
     name: Option<String>,
+    algorithm: Option<AlgorithmId>,
     pin_policy: Option<PinPolicy>,
     touch_policy: Option<TouchPolicy>,
+    pin: Option<String>,
+    management_key: Option<MgmKey>,
+    migrate: bool,
 }
 
 impl IdentityBuilder {
@@ -31,8 +37,12 @@ impl IdentityBuilder {
         IdentityBuilder {
             slot,
             name: None,
+            algorithm: None,
             pin_policy: None,
             touch_policy: None,
+            pin: None,
+            management_key: None,
+            migrate: true,
             force: false,
         }
     }
@@ -42,6 +52,11 @@ impl IdentityBuilder {
         self
     }
 
+    pub(crate) fn with_algorithm(mut self, algorithm: Option<AlgorithmId>) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
     pub(crate) fn with_pin_policy(mut self, pin_policy: Option<PinPolicy>) -> Self {
         self.pin_policy = pin_policy;
         self
@@ -52,12 +67,32 @@ impl IdentityBuilder {
         self
     }
 
+    /// Sets the PIN used to unlock the YubiKey, bypassing the interactive prompt.
+    pub(crate) fn with_pin(mut self, pin: Option<String>) -> Self {
+        self.pin = pin;
+        self
+    }
+
+    /// Sets the management key used to unlock the YubiKey, bypassing the interactive prompt.
+    pub(crate) fn with_management_key(mut self, management_key: Option<MgmKey>) -> Self {
+        self.management_key = management_key;
+        self
+    }
+
+    /// Controls whether a YubiKey still using its factory-default PIN and management
+    /// key is migrated to a fresh PIN and a PIN-protected management key. Defaults to
+    /// `true`; disable for YubiKeys whose secrets are managed by external tooling.
+    pub(crate) fn migrate_defaults(mut self, migrate: bool) -> Self {
+        self.migrate = migrate;
+        self
+    }
+
     pub(crate) fn force(mut self, force: bool) -> Self {
         self.force = force;
         self
     }
 
-    pub(crate) fn build(self, yubikey: &mut YubiKey) -> Result<(Stub, Recipient, String), Error> {
+    pub(crate) fn build(self, yubikey: &mut YubiKey) -> Result<(Stub, Epk, String), Error> {
         let slot = match self.slot {
             Some(slot) => {
                 if !self.force {
@@ -90,11 +125,12 @@ impl IdentityBuilder {
 
         let pin_policy = self.pin_policy.unwrap_or(DEFAULT_PIN_POLICY);
         let touch_policy = self.touch_policy.unwrap_or(DEFAULT_TOUCH_POLICY);
+        let algorithm = self.algorithm.unwrap_or(DEFAULT_ALGORITHM);
 
         // No need to ask for users to enter their PIN if the PIN policy requires it,
         // because here we _always_ require them to enter their PIN in order to access the
         // protected management key (which is necessary in order to generate identities).
-        yubikey::manage(yubikey)?;
+        yubikey::manage(yubikey, self.pin, self.management_key, self.migrate)?;
 
         if let TouchPolicy::Never = touch_policy {
             // No need to touch YubiKey
@@ -106,15 +142,18 @@ impl IdentityBuilder {
         let generated = yubikey_generate(
             yubikey,
             SlotId::Retired(slot),
-            AlgorithmId::EccP256,
+            algorithm,
             pin_policy,
             touch_policy,
         )?;
 
-        let recipient = match &generated {
-            PublicKeyInfo::EcP256(pubkey) => {
-                Recipient::from_pubkey(*pubkey).expect("YubiKey generates a valid pubkey")
-            }
+        let recipient: Epk = match &generated {
+            PublicKeyInfo::EcP256(pubkey) => p256::Recipient::from_pubkey(*pubkey)
+                .expect("YubiKey generates a valid pubkey")
+                .into(),
+            PublicKeyInfo::EcP384(pubkey) => p384::Recipient::from_pubkey(*pubkey)
+                .expect("YubiKey generates a valid pubkey")
+                .into(),
             _ => unreachable!(),
         };
         let stub = Stub::new(yubikey.serial(), slot, &recipient);
@@ -0,0 +1,325 @@
+//! A second, PIV-independent identity backend built on the FIDO2/CTAP2 `hmac-secret`
+//! extension.
+//!
+//! Unlike the PIV identities in [`crate::yubikey`], an `hmac-secret` identity doesn't
+//! occupy a PIV slot: it is a non-resident FIDO2 credential, and unwrapping (or, since
+//! the secret is symmetric rather than a keypair, *wrapping*) a file key both require a
+//! `GetAssertion` round-trip with the authenticator, and therefore a touch. There is no
+//! ECDH involved on our side; [`ctap_hid_fido2`] drives the CTAP2 PIN/UV protocol
+//! dance (establishing a shared secret with the authenticator's key-agreement key,
+//! obtaining a `pinUvAuthToken`, and encrypting `salt1` under it) whenever the
+//! authenticator reports that user verification is required.
+
+use age_core::{
+    format::{FileKey, Stanza},
+    primitives::{aead_decrypt, aead_encrypt, hkdf},
+};
+use age_plugin::identity::Callbacks;
+use bech32::ToBase32;
+use ctap_hid_fido2::{HidParam, MakeCredentialArgsBuilder, GetAssertionArgsBuilder};
+use rand::{rngs::OsRng, RngCore};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::io;
+
+use crate::error::Error;
+
+pub(crate) const RECIPIENT_PREFIX: &str = "age1yubikeyhmac";
+pub(crate) const IDENTITY_PREFIX: &str = "age-plugin-yubikey-hmac-secret-";
+
+const STANZA_TAG: &str = "yubikey-hmac-secret";
+const STANZA_KEY_LABEL: &[u8] = b"age-encryption.org/v1/yubikey-hmac-secret";
+
+const TAG_BYTES: usize = 4;
+const SALT_BYTES: usize = 32;
+const ENCRYPTED_FILE_KEY_BYTES: usize = 32;
+
+const RP_ID: &str = "age-plugin-yubikey";
+
+/// A reference to a non-resident FIDO2 `hmac-secret` credential.
+#[derive(Clone)]
+pub(crate) struct Recipient {
+    credential_id: Vec<u8>,
+}
+
+impl Recipient {
+    pub(crate) fn from_credential_id(credential_id: Vec<u8>) -> Self {
+        Recipient { credential_id }
+    }
+
+    pub(crate) fn to_string(&self) -> String {
+        bech32::encode(RECIPIENT_PREFIX, self.credential_id.to_base32()).expect("HRP is valid")
+    }
+
+    pub(crate) fn tag(&self) -> [u8; TAG_BYTES] {
+        let tag = Sha256::digest(self.to_string().as_bytes());
+        (&tag[0..TAG_BYTES]).try_into().expect("length is correct")
+    }
+}
+
+/// A reference to an age identity backed by a FIDO2 `hmac-secret` credential.
+#[derive(Debug)]
+pub(crate) struct Stub {
+    pub(crate) credential_id: Vec<u8>,
+    pub(crate) tag: [u8; TAG_BYTES],
+    identity_index: usize,
+}
+
+impl PartialEq for Stub {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_bytes().eq(&other.to_bytes())
+    }
+}
+
+impl Stub {
+    pub(crate) fn new(credential_id: Vec<u8>, recipient: &Recipient) -> Self {
+        Stub {
+            credential_id,
+            tag: recipient.tag(),
+            identity_index: 0,
+        }
+    }
+
+    /// Parses a stub from the length-prefixed `credential_id` + `tag` encoding
+    /// produced by [`Stub::to_bytes`], as found in an `age-plugin-yubikey-hmac-secret-`
+    /// identity string.
+    pub(crate) fn from_bytes(bytes: &[u8], identity_index: usize) -> Option<Self> {
+        let credential_id_len = *bytes.first()? as usize;
+        let credential_id = bytes.get(1..1 + credential_id_len)?.to_vec();
+        let tag = bytes
+            .get(1 + credential_id_len..1 + credential_id_len + TAG_BYTES)?
+            .try_into()
+            .ok()?;
+        Some(Stub {
+            credential_id,
+            tag,
+            identity_index,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.credential_id.len() + TAG_BYTES);
+        bytes.push(self.credential_id.len() as u8);
+        bytes.extend_from_slice(&self.credential_id);
+        bytes.extend_from_slice(&self.tag);
+        bytes
+    }
+
+    /// Serializes this stub as a string.
+    pub(crate) fn to_string(&self) -> String {
+        bech32::encode(IDENTITY_PREFIX, self.to_bytes().to_base32())
+            .expect("HRP is valid")
+            .to_uppercase()
+    }
+
+    pub(crate) fn matches(&self, line: &RecipientLine) -> bool {
+        self.tag == line.tag
+    }
+
+    /// Unwraps a file key by running CTAP2 `GetAssertion` against the credential.
+    /// Unlike the PIV path, this unconditionally requires the user to touch the
+    /// authenticator (and enter their FIDO PIN, if the authenticator requires user
+    /// verification), so we always emit the touch prompt first.
+    pub(crate) fn unwrap_file_key(
+        &self,
+        line: &RecipientLine,
+        callbacks: &mut dyn Callbacks,
+    ) -> io::Result<Result<FileKey, ()>> {
+        assert_eq!(self.tag, line.tag);
+
+        callbacks.message("👆 Touch your security key to decrypt the file key")?;
+
+        let secret = match get_hmac_secret(&self.credential_id, line.salt1) {
+            Ok(secret) => secret,
+            Err(_) => return Ok(Err(())),
+        };
+        let enc_key = hkdf(&line.salt1, STANZA_KEY_LABEL, &secret);
+
+        Ok(
+            aead_decrypt(&enc_key, age_core::format::FILE_KEY_BYTES, &line.encrypted_file_key)
+                .map(|pt| {
+                    TryInto::<[u8; age_core::format::FILE_KEY_BYTES]>::try_into(&pt[..])
+                        .unwrap()
+                        .into()
+                })
+                .map_err(|_| ()),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RecipientLine {
+    pub(crate) tag: [u8; TAG_BYTES],
+    pub(crate) credential_id: Vec<u8>,
+    pub(crate) salt1: [u8; SALT_BYTES],
+    pub(crate) encrypted_file_key: [u8; ENCRYPTED_FILE_KEY_BYTES],
+}
+
+impl From<RecipientLine> for Stanza {
+    fn from(r: RecipientLine) -> Self {
+        Stanza {
+            tag: STANZA_TAG.to_owned(),
+            args: vec![
+                base64::encode_config(&r.tag, base64::STANDARD_NO_PAD),
+                base64::encode_config(&r.credential_id, base64::STANDARD_NO_PAD),
+                base64::encode_config(&r.salt1, base64::STANDARD_NO_PAD),
+            ],
+            body: r.encrypted_file_key.to_vec(),
+        }
+    }
+}
+
+impl RecipientLine {
+    pub(super) fn from_stanza(s: &Stanza) -> Option<Result<Self, ()>> {
+        if s.tag != STANZA_TAG {
+            return None;
+        }
+
+        fn base64_arg(arg: &str) -> Option<Vec<u8>> {
+            base64::decode_config(arg, base64::STANDARD_NO_PAD).ok()
+        }
+
+        let tag = s
+            .args
+            .get(0)
+            .and_then(|arg| base64_arg(arg))
+            .and_then(|bytes| bytes.try_into().ok());
+        let credential_id = s.args.get(1).and_then(|arg| base64_arg(arg));
+        let salt1 = s
+            .args
+            .get(2)
+            .and_then(|arg| base64_arg(arg))
+            .and_then(|bytes| bytes.try_into().ok());
+
+        Some(match (tag, credential_id, salt1) {
+            (Some(tag), Some(credential_id), Some(salt1)) => Ok(RecipientLine {
+                tag,
+                credential_id,
+                salt1,
+                encrypted_file_key: s.body[..].try_into().ok()?,
+            }),
+            _ => Err(()),
+        })
+    }
+
+    /// Wraps a file key to an `hmac-secret` credential. Like unwrapping, this requires
+    /// touching the authenticator: the wrapping key is `HMAC-SHA256(CredRandom,
+    /// salt1)`, which only the authenticator holding the credential can compute.
+    pub(crate) fn wrap_file_key(file_key: &FileKey, recipient: &Recipient) -> Result<Self, Error> {
+        let mut salt1 = [0; SALT_BYTES];
+        OsRng.fill_bytes(&mut salt1);
+
+        let secret = get_hmac_secret(&recipient.credential_id, salt1)?;
+        let enc_key = hkdf(&salt1, STANZA_KEY_LABEL, &secret);
+
+        let mut encrypted_file_key = [0; ENCRYPTED_FILE_KEY_BYTES];
+        encrypted_file_key.copy_from_slice(&aead_encrypt(&enc_key, file_key.expose_secret()));
+
+        Ok(RecipientLine {
+            tag: recipient.tag(),
+            credential_id: recipient.credential_id.clone(),
+            salt1,
+            encrypted_file_key,
+        })
+    }
+}
+
+/// Creates a new non-resident `hmac-secret` credential and returns the stub/recipient
+/// pair for it. Requires the user to touch the authenticator.
+pub(crate) fn generate(name: &str) -> Result<(Stub, Recipient), Error> {
+    let device = first_fido2_device()?;
+
+    let args = MakeCredentialArgsBuilder::new(RP_ID, name.as_bytes())
+        .without_resident_key()
+        .extensions(&[ctap_hid_fido2::Extension::HmacSecret(Some(true))])
+        .build();
+
+    let credential_id = ctap_hid_fido2::make_credential(&device, &args, None)?
+        .credential_descriptor
+        .id;
+
+    let recipient = Recipient::from_credential_id(credential_id.clone());
+    let stub = Stub::new(credential_id, &recipient);
+
+    Ok((stub, recipient))
+}
+
+/// Runs CTAP2 `GetAssertion` with `salt1` in the `hmac-secret` extension input,
+/// returning `HMAC-SHA256(CredRandom, salt1)` as computed by the authenticator.
+///
+/// If the authenticator requires user verification, `ctap_hid_fido2` runs the CTAP2
+/// PIN/UV protocol (ECDH with the authenticator's key-agreement key to establish a
+/// shared secret, then a `pinUvAuthToken`) and uses it to encrypt `salt1` before
+/// sending it, as required by the `hmac-secret` extension.
+fn get_hmac_secret(credential_id: &[u8], salt1: [u8; SALT_BYTES]) -> Result<[u8; 32], Error> {
+    let device = first_fido2_device()?;
+
+    let args = GetAssertionArgsBuilder::new(RP_ID, &[])
+        .credential_id(credential_id)
+        .extensions(&[ctap_hid_fido2::Extension::HmacSecret(Some(salt1))])
+        .build();
+
+    let assertion = ctap_hid_fido2::get_assertion(&device, &args, None)?;
+
+    assertion.extensions.hmac_secret.ok_or(Error::Fido2Failed)
+}
+
+fn first_fido2_device() -> Result<HidParam, Error> {
+    ctap_hid_fido2::get_fidokey_devices()
+        .into_iter()
+        .next()
+        .map(|(_, param)| param)
+        .ok_or(Error::Fido2DeviceNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stub_round_trip() {
+        let stub = Stub {
+            credential_id: vec![1, 2, 3, 4, 5],
+            tag: [7; TAG_BYTES],
+            identity_index: 0,
+        };
+
+        let encoded = stub.to_bytes();
+        assert_eq!(Stub::from_bytes(&encoded, 0), Some(stub));
+    }
+
+    #[test]
+    fn recipient_line_stanza_round_trip() {
+        let line = RecipientLine {
+            tag: [1; TAG_BYTES],
+            credential_id: vec![9, 8, 7],
+            salt1: [2; SALT_BYTES],
+            encrypted_file_key: [3; ENCRYPTED_FILE_KEY_BYTES],
+        };
+        let (tag, credential_id, salt1, encrypted_file_key) = (
+            line.tag,
+            line.credential_id.clone(),
+            line.salt1,
+            line.encrypted_file_key,
+        );
+
+        let stanza: Stanza = line.into();
+        let parsed = RecipientLine::from_stanza(&stanza).unwrap().unwrap();
+
+        assert_eq!(parsed.tag, tag);
+        assert_eq!(parsed.credential_id, credential_id);
+        assert_eq!(parsed.salt1, salt1);
+        assert_eq!(parsed.encrypted_file_key, encrypted_file_key);
+    }
+
+    #[test]
+    fn recipient_line_from_stanza_rejects_wrong_tag() {
+        let stanza = Stanza {
+            tag: "not-hmac-secret".to_owned(),
+            args: vec![],
+            body: vec![],
+        };
+        assert!(RecipientLine::from_stanza(&stanza).is_none());
+    }
+}
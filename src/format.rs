@@ -3,31 +3,113 @@ use age_core::{
     primitives::{aead_encrypt, hkdf},
 };
 use ring::{
-    agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, ECDH_P256},
+    agreement::{agree_ephemeral, Algorithm, EphemeralPrivateKey, UnparsedPublicKey, ECDH_P256, ECDH_P384},
     rand::SystemRandom,
 };
 use secrecy::ExposeSecret;
 use std::convert::TryInto;
 
-use crate::{p256::Recipient, STANZA_TAG};
+use crate::{p256, p384};
 
-pub(crate) const STANZA_KEY_LABEL: &[u8] = b"age-encryption.org/v1/piv-p256";
+const STANZA_TAG_P256: &str = "piv-p256";
+const STANZA_TAG_P384: &str = "piv-p384";
+
+pub(crate) const STANZA_KEY_LABEL_P256: &[u8] = b"age-encryption.org/v1/piv-p256";
+pub(crate) const STANZA_KEY_LABEL_P384: &[u8] = b"age-encryption.org/v1/piv-p384";
 
 const TAG_BYTES: usize = 4;
-const EPK_BYTES: usize = 33;
+const EPK_BYTES_P256: usize = 33;
+const EPK_BYTES_P384: usize = 49;
 const ENCRYPTED_FILE_KEY_BYTES: usize = 32;
 
+/// An ephemeral (or static) public key on one of the curves we support.
+#[derive(Debug, Clone)]
+pub(crate) enum Epk {
+    P256(p256::Recipient),
+    P384(p384::Recipient),
+}
+
+impl Epk {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            Epk::P256(pk) => pk.as_bytes(),
+            Epk::P384(pk) => pk.as_bytes(),
+        }
+    }
+
+    pub(crate) fn tag(&self) -> [u8; TAG_BYTES] {
+        match self {
+            Epk::P256(pk) => pk.tag(),
+            Epk::P384(pk) => pk.tag(),
+        }
+    }
+
+    fn stanza_tag(&self) -> &'static str {
+        match self {
+            Epk::P256(_) => STANZA_TAG_P256,
+            Epk::P384(_) => STANZA_TAG_P384,
+        }
+    }
+
+    pub(crate) fn hkdf_label(&self) -> &'static [u8] {
+        match self {
+            Epk::P256(_) => STANZA_KEY_LABEL_P256,
+            Epk::P384(_) => STANZA_KEY_LABEL_P384,
+        }
+    }
+
+    pub(crate) fn algorithm_id(&self) -> yubikey_piv::key::AlgorithmId {
+        match self {
+            Epk::P256(_) => yubikey_piv::key::AlgorithmId::EccP256,
+            Epk::P384(_) => yubikey_piv::key::AlgorithmId::EccP384,
+        }
+    }
+
+    fn ecdh_algorithm(&self) -> &'static Algorithm {
+        match self {
+            Epk::P256(_) => &ECDH_P256,
+            Epk::P384(_) => &ECDH_P384,
+        }
+    }
+
+    pub(crate) fn decompressed(&self) -> Vec<u8> {
+        match self {
+            Epk::P256(pk) => pk.decompress().as_bytes().to_vec(),
+            Epk::P384(pk) => pk.decompress().as_bytes().to_vec(),
+        }
+    }
+
+    pub(crate) fn to_string(&self) -> String {
+        match self {
+            Epk::P256(pk) => pk.to_string(),
+            Epk::P384(pk) => pk.to_string(),
+        }
+    }
+}
+
+impl From<p256::Recipient> for Epk {
+    fn from(pk: p256::Recipient) -> Self {
+        Epk::P256(pk)
+    }
+}
+
+impl From<p384::Recipient> for Epk {
+    fn from(pk: p384::Recipient) -> Self {
+        Epk::P384(pk)
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct RecipientLine {
     pub(crate) tag: [u8; TAG_BYTES],
-    pub(crate) epk: Recipient,
+    pub(crate) epk: Epk,
     pub(crate) encrypted_file_key: [u8; ENCRYPTED_FILE_KEY_BYTES],
 }
 
 impl From<RecipientLine> for Stanza {
     fn from(r: RecipientLine) -> Self {
         Stanza {
-            tag: STANZA_TAG.to_owned(),
+            tag: r.epk.stanza_tag().to_owned(),
             args: vec![
                 base64::encode_config(&r.tag, base64::STANDARD_NO_PAD),
                 base64::encode_config(r.epk.as_bytes(), base64::STANDARD_NO_PAD),
@@ -39,9 +121,11 @@ impl From<RecipientLine> for Stanza {
 
 impl RecipientLine {
     pub(super) fn from_stanza(s: &Stanza) -> Option<Result<Self, ()>> {
-        if s.tag != STANZA_TAG {
-            return None;
-        }
+        let epk_bytes = match s.tag.as_str() {
+            STANZA_TAG_P256 => EPK_BYTES_P256,
+            STANZA_TAG_P384 => EPK_BYTES_P384,
+            _ => return None,
+        };
 
         fn base64_arg<A: AsRef<[u8]>, B: AsMut<[u8]>>(arg: &A, mut buf: B) -> Option<B> {
             if arg.as_ref().len() != ((4 * buf.as_mut().len()) + 2) / 3 {
@@ -58,11 +142,14 @@ impl RecipientLine {
             .args
             .get(0)
             .and_then(|arg| base64_arg(arg, [0; TAG_BYTES]));
-        let epk = s
-            .args
-            .get(1)
-            .and_then(|arg| base64_arg(arg, vec![0; EPK_BYTES]))
-            .and_then(|bytes| Recipient::from_bytes(&bytes));
+        let epk = s.args.get(1).and_then(|arg| {
+            let bytes = base64_arg(arg, vec![0; epk_bytes])?;
+            match s.tag.as_str() {
+                STANZA_TAG_P256 => p256::Recipient::from_bytes(&bytes).map(Epk::P256),
+                STANZA_TAG_P384 => p384::Recipient::from_bytes(&bytes).map(Epk::P384),
+                _ => unreachable!(),
+            }
+        });
 
         Some(match (tag, epk) {
             (Some(tag), Some(epk)) => Ok(RecipientLine {
@@ -74,22 +161,29 @@ impl RecipientLine {
         })
     }
 
-    pub(crate) fn wrap_file_key(file_key: &FileKey, pk: &Recipient) -> Self {
+    pub(crate) fn wrap_file_key(file_key: &FileKey, pk: &Epk) -> Self {
         let rng = SystemRandom::new();
+        let alg = pk.ecdh_algorithm();
+
+        let esk = EphemeralPrivateKey::generate(alg, &rng).expect("TODO handle failing RNG");
+        let epk: Epk = match pk {
+            Epk::P256(_) => p256::Recipient::from_bytes(esk.compute_public_key().expect("TODO").as_ref())
+                .expect("epk is valid")
+                .into(),
+            Epk::P384(_) => p384::Recipient::from_bytes(esk.compute_public_key().expect("TODO").as_ref())
+                .expect("epk is valid")
+                .into(),
+        };
 
-        let esk = EphemeralPrivateKey::generate(&ECDH_P256, &rng).expect("TODO handle failing RNG");
-        let epk = Recipient::from_bytes(esk.compute_public_key().expect("TODO").as_ref())
-            .expect("epk is valid");
-
-        let pk_uncompressed = pk.decompress();
-        let pk_ring = UnparsedPublicKey::new(&ECDH_P256, pk_uncompressed.as_bytes());
+        let pk_uncompressed = pk.decompressed();
+        let pk_ring = UnparsedPublicKey::new(alg, &pk_uncompressed);
 
         let enc_key = agree_ephemeral(esk, &pk_ring, (), |shared_secret| {
             let mut salt = vec![];
             salt.extend_from_slice(epk.as_bytes());
             salt.extend_from_slice(pk.as_bytes());
 
-            Ok(hkdf(&salt, STANZA_KEY_LABEL, shared_secret))
+            Ok(hkdf(&salt, pk.hkdf_label(), shared_secret))
         })
         .expect("keys are correct");
 
@@ -106,3 +200,55 @@ impl RecipientLine {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use age_core::format::FILE_KEY_BYTES;
+
+    use super::*;
+
+    // The P-256 and P-384 generator points, used as stand-ins for a recipient's
+    // static public key.
+    const P256_PK: &str = "036b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296";
+    const P384_PK: &str = "03aa87ca22be8b05378eb1c71ef320ad746e1d3b628ba79b9859f741e082542a385502f25dbf55296c3a545e3872760ab7";
+
+    fn wrap_stanza_round_trip(pk: Epk) {
+        let file_key: FileKey = [7; FILE_KEY_BYTES].into();
+
+        let line = RecipientLine::wrap_file_key(&file_key, &pk);
+        let expected_tag = line.tag;
+        let expected_file_key = line.encrypted_file_key;
+
+        let stanza: Stanza = line.into();
+        let parsed = RecipientLine::from_stanza(&stanza).unwrap().unwrap();
+
+        assert_eq!(parsed.tag, expected_tag);
+        assert_eq!(parsed.tag, pk.tag());
+        assert_eq!(parsed.encrypted_file_key, expected_file_key);
+        assert_eq!(parsed.epk.as_bytes().len(), pk.as_bytes().len());
+    }
+
+    #[test]
+    fn wrap_stanza_round_trip_p256() {
+        let bytes = hex::decode(P256_PK).unwrap();
+        let pk = p256::Recipient::from_bytes(&bytes).unwrap();
+        wrap_stanza_round_trip(Epk::P256(pk));
+    }
+
+    #[test]
+    fn wrap_stanza_round_trip_p384() {
+        let bytes = hex::decode(P384_PK).unwrap();
+        let pk = p384::Recipient::from_bytes(&bytes).unwrap();
+        wrap_stanza_round_trip(Epk::P384(pk));
+    }
+
+    #[test]
+    fn from_stanza_rejects_unknown_tag() {
+        let stanza = Stanza {
+            tag: "not-a-piv-stanza".to_owned(),
+            args: vec![],
+            body: vec![],
+        };
+        assert!(RecipientLine::from_stanza(&stanza).is_none());
+    }
+}
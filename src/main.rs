@@ -2,19 +2,23 @@ use age_plugin::run_state_machine;
 use gumdrop::Options;
 use yubikey_piv::{
     certificate::PublicKeyInfo,
-    key::{RetiredSlotId, SlotId},
+    key::{AlgorithmId, RetiredSlotId, SlotId},
     policy::{PinPolicy, TouchPolicy},
     Key, Readers,
 };
 
 mod builder;
 mod error;
+mod fido2;
+mod format;
 mod p256;
+mod p384;
 mod plugin;
 mod util;
 mod yubikey;
 
 use error::Error;
+use format::Epk;
 
 const PLUGIN_NAME: &str = "age-plugin-yubikey";
 const RECIPIENT_PREFIX: &str = "age1yubikey";
@@ -48,6 +52,23 @@ struct PluginOptions {
     #[options(help = "Print this help message and exit.")]
     help: bool,
 
+    #[options(help = "One of [p256, p384]. Defaults to 'p256'.", no_short)]
+    algorithm: Option<String>,
+
+    #[options(
+        help = "Print a PIV attestation certificate chain for the identity's slot.",
+        no_short
+    )]
+    attest: bool,
+
+    #[options(
+        help = "With --generate, create a FIDO2 hmac-secret identity instead of a PIV one. \
+                Does not occupy a PIV slot; only a touch (and optionally a FIDO PIN) is \
+                needed to decrypt.",
+        no_short
+    )]
+    fido2: bool,
+
     #[options(
         help = "Run the given age plugin state machine. Internal use only.",
         meta = "STATE-MACHINE",
@@ -76,6 +97,27 @@ struct PluginOptions {
     )]
     name: Option<String>,
 
+    #[options(
+        help = "Don't migrate a default PIN/management key to fresh ones during --generate.",
+        no_short
+    )]
+    no_migrate: bool,
+
+    #[options(
+        help = "PIN to unlock the YubiKey with. Falls back to AGE_PLUGIN_YUBIKEY_PIN. \
+                Enables non-interactive use; prompts interactively if absent.",
+        no_short
+    )]
+    pin: Option<String>,
+
+    #[options(
+        help = "Management key (hex) to unlock the YubiKey with. Falls back to \
+                AGE_PLUGIN_YUBIKEY_MGMT_KEY. Enables non-interactive use; prompts \
+                interactively if absent.",
+        no_short
+    )]
+    management_key: Option<String>,
+
     #[options(help = "One of [always, once, never]. Defaults to 'once'.", no_short)]
     pin_policy: Option<String>,
 
@@ -86,10 +128,16 @@ struct PluginOptions {
     serial: Option<u32>,
 
     #[options(
-        help = "Specify which slot to use. Defaults to first usable slot.",
+        help = "Specify which slot to use (1-20, R1-R20, or 0x82-0x95). Defaults to first usable slot.",
         no_short
     )]
-    slot: Option<u8>,
+    slot: Option<String>,
+
+    #[options(
+        help = "With --identity or --list, print the slot's P-256 key as an OpenSSH public key.",
+        no_short
+    )]
+    ssh: bool,
 
     #[options(
         help = "One of [always, cached, never]. Defaults to 'always'.",
@@ -99,16 +147,17 @@ struct PluginOptions {
 }
 
 fn generate(opts: PluginOptions) -> Result<(), Error> {
+    if opts.fido2 {
+        let name = opts
+            .name
+            .unwrap_or_else(|| "age identity (FIDO2)".to_owned());
+        let (stub, recipient) = fido2::generate(&name)?;
+        util::print_fido2_identity(stub, recipient);
+        return Ok(());
+    }
+
     let serial = opts.serial.map(|s| s.into());
-    let slot = opts
-        .slot
-        .map(|slot| {
-            USABLE_SLOTS
-                .get(slot as usize - 1)
-                .cloned()
-                .ok_or(Error::InvalidSlot(slot))
-        })
-        .transpose()?;
+    let slot = opts.slot.map(util::parse_slot).transpose()?;
     let pin_policy = opts
         .pin_policy
         .map(util::pin_policy_from_string)
@@ -117,41 +166,57 @@ fn generate(opts: PluginOptions) -> Result<(), Error> {
         .touch_policy
         .map(util::touch_policy_from_string)
         .transpose()?;
+    let algorithm = opts
+        .algorithm
+        .map(util::algorithm_from_string)
+        .transpose()?;
+    let pin = opts
+        .pin
+        .or_else(|| std::env::var("AGE_PLUGIN_YUBIKEY_PIN").ok());
+    let management_key = opts
+        .management_key
+        .or_else(|| std::env::var("AGE_PLUGIN_YUBIKEY_MGMT_KEY").ok())
+        .map(util::mgm_key_from_hex)
+        .transpose()?;
 
     let mut yubikey = yubikey::open(serial)?;
 
     let (stub, recipient, created) = builder::IdentityBuilder::new(slot)
         .with_name(opts.name)
+        .with_algorithm(algorithm)
         .with_pin_policy(pin_policy)
         .with_touch_policy(touch_policy)
+        .with_pin(pin)
+        .with_management_key(management_key)
+        .migrate_defaults(!opts.no_migrate)
         .force(opts.force)
         .build(&mut yubikey)?;
 
+    let slot = stub.slot;
     util::print_identity(stub, recipient, &created);
 
+    if opts.attest {
+        util::print_attestation(&mut yubikey, slot)?;
+    }
+
     Ok(())
 }
 
 fn identity(opts: PluginOptions) -> Result<(), Error> {
     let serial = opts.serial.map(|s| s.into());
-    let slot = opts
-        .slot
-        .map(|slot| {
-            USABLE_SLOTS
-                .get(slot as usize - 1)
-                .cloned()
-                .ok_or(Error::InvalidSlot(slot))
-        })
-        .transpose()?;
+    let slot = opts.slot.map(util::parse_slot).transpose()?;
 
     let mut yubikey = yubikey::open(serial)?;
 
     let mut keys = Key::list(&mut yubikey)?.into_iter().filter_map(|key| {
         // - We only use the retired slots.
-        // - Only P-256 keys are compatible with us.
+        // - Only P-256 and P-384 keys are compatible with us.
         match (key.slot(), key.certificate().subject_pki()) {
             (SlotId::Retired(slot), PublicKeyInfo::EcP256(pubkey)) => {
-                p256::Recipient::from_pubkey(*pubkey).map(|r| (key, slot, r))
+                p256::Recipient::from_pubkey(*pubkey).map(|r| (key, slot, Epk::P256(r)))
+            }
+            (SlotId::Retired(slot), PublicKeyInfo::EcP384(pubkey)) => {
+                p384::Recipient::from_pubkey(*pubkey).map(|r| (key, slot, Epk::P384(r)))
             }
             _ => None,
         }
@@ -181,12 +246,20 @@ fn identity(opts: PluginOptions) -> Result<(), Error> {
     let (_, cert) = x509_parser::parse_x509_der(key.certificate().as_ref()).unwrap();
     let created = cert.validity().not_before.to_rfc2822();
 
-    util::print_identity(stub, recipient, &created);
+    if opts.ssh {
+        println!("{}", util::ssh_public_key(&recipient, &stub.tag)?);
+    } else {
+        util::print_identity(stub, recipient, &created);
+    }
+
+    if opts.attest {
+        util::print_attestation(&mut yubikey, slot)?;
+    }
 
     Ok(())
 }
 
-fn list(all: bool) -> Result<(), Error> {
+fn list(all: bool, ssh: bool) -> Result<(), Error> {
     let mut readers = Readers::open()?;
 
     for reader in readers.iter()? {
@@ -199,15 +272,30 @@ fn list(all: bool) -> Result<(), Error> {
                 _ => continue,
             };
 
-            // Only P-256 keys are compatible with us.
-            let recipient = match key.certificate().subject_pki() {
+            // Only P-256 and P-384 keys are compatible with us.
+            let recipient: Epk = match key.certificate().subject_pki() {
                 PublicKeyInfo::EcP256(pubkey) => match p256::Recipient::from_pubkey(*pubkey) {
-                    Some(recipient) => recipient,
+                    Some(recipient) => recipient.into(),
+                    None => continue,
+                },
+                PublicKeyInfo::EcP384(pubkey) => match p384::Recipient::from_pubkey(*pubkey) {
+                    Some(recipient) => recipient.into(),
                     None => continue,
                 },
                 _ => continue,
             };
 
+            if ssh {
+                match util::ssh_public_key(&recipient, &recipient.tag()) {
+                    Ok(ssh_key) => println!("{}", ssh_key),
+                    Err(_) => eprintln!(
+                        "Skipping a key on serial {} that is incompatible with SSH",
+                        yubikey.serial(),
+                    ),
+                }
+                continue;
+            }
+
             let (_, cert) = x509_parser::parse_x509_der(key.certificate().as_ref()).unwrap();
             let (name, pin_policy, touch_policy) =
                 match util::extract_name_and_policies(&mut yubikey, &key, &cert, all) {
@@ -261,9 +349,9 @@ fn main() -> Result<(), Error> {
     } else if opts.identity {
         identity(opts)
     } else if opts.list {
-        list(false)
+        list(false, opts.ssh)
     } else if opts.list_all {
-        list(true)
+        list(true, opts.ssh)
     } else {
         // TODO: CLI identity generation
         Ok(())
@@ -0,0 +1,101 @@
+use bech32::ToBase32;
+use elliptic_curve::sec1::EncodedPoint;
+use p384::NistP384;
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::p256::TAG_BYTES;
+
+pub(crate) const RECIPIENT_PREFIX: &str = "age1yubikeyp384";
+
+/// Wrapper around a compressed secp384r1 curve point.
+#[derive(Clone)]
+pub struct Recipient(EncodedPoint<NistP384>);
+
+impl fmt::Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Recipient({:?})", self.as_bytes())
+    }
+}
+
+impl Recipient {
+    /// Attempts to parse a valid secp384r1 public key from a byte slice.
+    ///
+    /// The slice must contain an SEC-1-encoded public key.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Self::from_pubkey(EncodedPoint::from_bytes(bytes).ok()?)
+    }
+
+    /// Attempts to parse a valid secp384r1 public key from its SEC-1 encoding.
+    pub(crate) fn from_pubkey(pubkey: EncodedPoint<NistP384>) -> Option<Self> {
+        if pubkey.is_compressed() {
+            if pubkey.decompress().is_some().into() {
+                Some(Recipient(pubkey))
+            } else {
+                None
+            }
+        } else {
+            Some(Recipient(pubkey.compress()))
+        }
+    }
+
+    /// Returns the compressed SEC-1 encoding of this public key.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    pub(crate) fn to_string(&self) -> String {
+        bech32::encode(RECIPIENT_PREFIX, self.as_bytes().to_base32()).expect("HRP is valid")
+    }
+
+    pub(crate) fn tag(&self) -> [u8; TAG_BYTES] {
+        let tag = Sha256::digest(self.to_string().as_bytes());
+        (&tag[0..TAG_BYTES]).try_into().expect("length is correct")
+    }
+
+    /// Returns the uncompressed SEC-1 encoding of this public key.
+    pub(crate) fn decompress(&self) -> EncodedPoint<NistP384> {
+        self.0.decompress().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The secp384r1 generator point, compressed.
+    const GENERATOR: &str = "03aa87ca22be8b05378eb1c71ef320ad746e1d3b628ba79b9859f741e082542a385502f25dbf55296c3a545e3872760ab7";
+
+    #[test]
+    fn from_bytes_round_trip() {
+        let bytes = hex::decode(GENERATOR).unwrap();
+        let recipient = Recipient::from_bytes(&bytes).unwrap();
+        assert_eq!(recipient.as_bytes(), &bytes[..]);
+    }
+
+    #[test]
+    fn from_bytes_compresses_uncompressed_input() {
+        let compressed = hex::decode(GENERATOR).unwrap();
+        let uncompressed = Recipient::from_bytes(&compressed)
+            .unwrap()
+            .decompress()
+            .as_bytes()
+            .to_vec();
+
+        let recipient = Recipient::from_bytes(&uncompressed).unwrap();
+        assert_eq!(recipient.as_bytes(), &compressed[..]);
+    }
+
+    #[test]
+    fn from_bytes_rejects_garbage() {
+        assert!(Recipient::from_bytes(&[0; 3]).is_none());
+    }
+
+    #[test]
+    fn tag_is_stable() {
+        let bytes = hex::decode(GENERATOR).unwrap();
+        let recipient = Recipient::from_bytes(&bytes).unwrap();
+        assert_eq!(recipient.tag(), recipient.tag());
+    }
+}
@@ -1,14 +1,58 @@
 use der_parser::oid::Oid;
+use std::convert::TryFrom;
 use x509_parser::X509Certificate;
 use yubikey_piv::{
+    certificate::{Certificate, PublicKeyInfo},
+    key::{attest, AlgorithmId, RetiredSlotId, SlotId},
     policy::{PinPolicy, TouchPolicy},
-    Key, YubiKey,
+    Key, MgmKey, YubiKey,
 };
 
-use crate::{error::Error, p256::Recipient, yubikey::Stub, PLUGIN_NAME};
+use crate::{error::Error, fido2, format::Epk, yubikey::Stub, PLUGIN_NAME, USABLE_SLOTS};
 
 const POLICY_EXTENSION_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 41482, 3, 8];
 
+/// Parses a `--slot` argument, accepting:
+/// - a 1-20 index into [`USABLE_SLOTS`];
+/// - the retired-slot nickname form `R1`..`R20`;
+/// - the raw PIV hex slot form `0x82`..`0x95`.
+pub(crate) fn parse_slot(s: String) -> Result<RetiredSlotId, Error> {
+    let by_index = |index: usize| {
+        USABLE_SLOTS
+            .get(index.wrapping_sub(1))
+            .cloned()
+            .ok_or_else(|| Error::InvalidSlot(s.clone()))
+    };
+
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        let byte = u8::from_str_radix(hex, 16).map_err(|_| Error::InvalidSlot(s.clone()))?;
+        return RetiredSlotId::try_from(byte).map_err(|_| Error::InvalidSlot(s.clone()));
+    }
+
+    if let Some(index) = s.strip_prefix('R').or_else(|| s.strip_prefix('r')) {
+        let index: usize = index.parse().map_err(|_| Error::InvalidSlot(s.clone()))?;
+        return by_index(index);
+    }
+
+    let index: usize = s.parse().map_err(|_| Error::InvalidSlot(s.clone()))?;
+    by_index(index)
+}
+
+/// Parses a management key given as a hex string (24 bytes, for either the TDES or
+/// the newer AES-192 management key algorithms).
+pub(crate) fn mgm_key_from_hex(s: String) -> Result<MgmKey, Error> {
+    let bytes = hex::decode(&s).map_err(|_| Error::InvalidManagementKey(s.clone()))?;
+    MgmKey::try_from(&bytes[..]).map_err(|_| Error::InvalidManagementKey(s))
+}
+
+pub(crate) fn algorithm_from_string(s: String) -> Result<AlgorithmId, Error> {
+    match s.as_str() {
+        "p256" => Ok(AlgorithmId::EccP256),
+        "p384" => Ok(AlgorithmId::EccP384),
+        _ => Err(Error::InvalidAlgorithm(s)),
+    }
+}
+
 pub(crate) fn pin_policy_from_string(s: String) -> Result<PinPolicy, Error> {
     match s.as_str() {
         "always" => Ok(PinPolicy::Always),
@@ -90,19 +134,7 @@ pub(crate) fn extract_name_and_policies(
             .extensions()
             .get(&Oid::from(POLICY_EXTENSION_OID).unwrap())
             .unwrap();
-        let pin_policy = match policy.value[0] {
-            0x01 => PinPolicy::Never,
-            0x02 => PinPolicy::Once,
-            0x03 => PinPolicy::Always,
-            _ => unreachable!(),
-        };
-        let touch_policy = match policy.value[1] {
-            0x01 => TouchPolicy::Never,
-            0x02 => TouchPolicy::Always,
-            0x03 => TouchPolicy::Cached,
-            _ => unreachable!(),
-        };
-        (Some(pin_policy), Some(touch_policy))
+        policies_from_extension_bytes(&policy.value)
     };
 
     extract_name(cert, all).map(|(name, ours)| {
@@ -110,14 +142,19 @@ pub(crate) fn extract_name_and_policies(
             let (pin_policy, touch_policy) = policies(&cert);
             (name, pin_policy, touch_policy)
         } else {
-            // We can extract the PIN and touch policies via an attestation. This
-            // is slow, but the user has asked for all compatible keys, so...
-            let (pin_policy, touch_policy) = match yubikey_piv::key::attest(yubikey, key.slot()) {
-                Ok(buf) => {
-                    let (_, c) = x509_parser::parse_x509_der(&buf).unwrap();
-                    policies(&c)
-                }
-                Err(_) => (None, None),
+            // We'd like the PIN and touch policies of keys we didn't create. Ask the
+            // YubiKey directly via GetMetadata (firmware 5.3+) where we can, since
+            // that's a single cheap APDU; only fall back to generating and parsing an
+            // attestation certificate on older firmware that doesn't support it.
+            let (pin_policy, touch_policy) = match slot_metadata(yubikey, key.slot()) {
+                Some(metadata) => (metadata.pin_policy, metadata.touch_policy),
+                None => match yubikey_piv::key::attest(yubikey, key.slot()) {
+                    Ok(buf) => {
+                        let (_, c) = x509_parser::parse_x509_der(&buf).unwrap();
+                        policies(&c)
+                    }
+                    Err(_) => (None, None),
+                },
             };
 
             (name, pin_policy, touch_policy)
@@ -125,7 +162,50 @@ pub(crate) fn extract_name_and_policies(
     })
 }
 
-pub(crate) fn print_identity(stub: Stub, recipient: Recipient, created: &str) {
+/// Decodes the PIN and touch policy bytes of a PIV attestation certificate's policy
+/// extension (`value[0]` is the PIN policy, `value[1]` is the touch policy).
+fn policies_from_extension_bytes(value: &[u8]) -> (Option<PinPolicy>, Option<TouchPolicy>) {
+    let pin_policy = match value[0] {
+        0x01 => PinPolicy::Never,
+        0x02 => PinPolicy::Once,
+        0x03 => PinPolicy::Always,
+        _ => unreachable!(),
+    };
+    let touch_policy = match value[1] {
+        0x01 => TouchPolicy::Never,
+        0x02 => TouchPolicy::Always,
+        0x03 => TouchPolicy::Cached,
+        _ => unreachable!(),
+    };
+    (Some(pin_policy), Some(touch_policy))
+}
+
+/// The fields of a PIV slot's metadata, as returned by the `GetMetadata` instruction.
+pub(crate) struct SlotMetadata {
+    pub(crate) pin_policy: Option<PinPolicy>,
+    pub(crate) touch_policy: Option<TouchPolicy>,
+    pub(crate) public_key: Option<PublicKeyInfo>,
+}
+
+/// Reads a slot's PIN/touch policy and public key via the PIV `GetMetadata`
+/// instruction, without generating (and parsing) an attestation certificate.
+///
+/// Returns `None` on firmware older than 5.3, which doesn't implement `GetMetadata`;
+/// callers should fall back to the attestation/certificate path in that case.
+pub(crate) fn slot_metadata(yubikey: &mut YubiKey, slot: SlotId) -> Option<SlotMetadata> {
+    match yubikey_piv::key::metadata(yubikey, slot) {
+        Ok(metadata) => Some(SlotMetadata {
+            pin_policy: metadata.pin_policy,
+            touch_policy: metadata.touch_policy,
+            public_key: metadata.public_key,
+        }),
+        // Firmware < 5.3 returns "Instruction not supported"; any other error (e.g. an
+        // empty slot) is just as unusable to us, so callers fall back the same way.
+        Err(_) => None,
+    }
+}
+
+pub(crate) fn print_identity(stub: Stub, recipient: Epk, created: &str) {
     let recipient = recipient.to_string();
     if !console::user_attended() {
         eprintln!("Recipient: {}", recipient);
@@ -135,3 +215,181 @@ pub(crate) fn print_identity(stub: Stub, recipient: Recipient, created: &str) {
     println!("# recipient: {}", recipient);
     println!("{}", stub.to_string());
 }
+
+/// Like [`print_identity`], for a FIDO2 `hmac-secret` identity. There's no on-device
+/// certificate to read a creation time from, so we just print the current time.
+pub(crate) fn print_fido2_identity(stub: fido2::Stub, recipient: fido2::Recipient) {
+    let recipient = recipient.to_string();
+    if !console::user_attended() {
+        eprintln!("Recipient: {}", recipient);
+    }
+
+    println!(
+        "# created: {}",
+        chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    );
+    println!("# recipient: {}", recipient);
+    println!("{}", stub.to_string());
+}
+
+/// Attests the given slot and prints the resulting leaf certificate, along with the
+/// device's intermediate attestation certificate (read from slot `0xf9`), as PEM.
+///
+/// The leaf chains to the intermediate, which chains to the Yubico PIV Root CA; a
+/// relying party can use this chain to confirm that the identity's private key was
+/// generated on-device and to check its PIN/touch policy independently of us.
+pub(crate) fn print_attestation(yubikey: &mut YubiKey, slot: RetiredSlotId) -> Result<(), Error> {
+    let leaf = attest(yubikey, SlotId::Retired(slot))?;
+    let intermediate = Certificate::read(yubikey, SlotId::Attestation)?;
+
+    print_pem_certificate(&leaf);
+    print_pem_certificate(intermediate.as_ref());
+
+    Ok(())
+}
+
+/// Serializes the P-256 key behind a slot as an `ecdsa-sha2-nistp256` OpenSSH public
+/// key line, so the same slot can be registered in `authorized_keys`.
+pub(crate) fn ssh_public_key(recipient: &Epk, tag: &[u8]) -> Result<String, Error> {
+    let pk = match recipient {
+        Epk::P256(pk) => pk,
+        Epk::P384(_) => return Err(Error::SshUnsupportedForAlgorithm),
+    };
+
+    fn write_string(blob: &mut Vec<u8>, s: &[u8]) {
+        blob.extend_from_slice(&(s.len() as u32).to_be_bytes());
+        blob.extend_from_slice(s);
+    }
+
+    let mut blob = Vec::new();
+    write_string(&mut blob, b"ecdsa-sha2-nistp256");
+    write_string(&mut blob, b"nistp256");
+    write_string(&mut blob, pk.decompress().as_bytes());
+
+    Ok(format!(
+        "ecdsa-sha2-nistp256 {} age identity {}",
+        base64::encode(&blob),
+        hex::encode(tag),
+    ))
+}
+
+fn print_pem_certificate(der: &[u8]) {
+    println!("-----BEGIN CERTIFICATE-----");
+    for line in base64::encode(der).as_bytes().chunks(64) {
+        println!("{}", std::str::from_utf8(line).expect("base64 is ASCII"));
+    }
+    println!("-----END CERTIFICATE-----");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_slot_by_index() {
+        assert_eq!(parse_slot("1".to_owned()).unwrap(), RetiredSlotId::R1);
+        assert_eq!(parse_slot("20".to_owned()).unwrap(), RetiredSlotId::R20);
+        assert!(parse_slot("0".to_owned()).is_err());
+        assert!(parse_slot("21".to_owned()).is_err());
+    }
+
+    #[test]
+    fn parse_slot_by_nickname() {
+        assert_eq!(parse_slot("R1".to_owned()).unwrap(), RetiredSlotId::R1);
+        assert_eq!(parse_slot("r1".to_owned()).unwrap(), RetiredSlotId::R1);
+        assert!(parse_slot("R0".to_owned()).is_err());
+        assert!(parse_slot("R21".to_owned()).is_err());
+    }
+
+    #[test]
+    fn parse_slot_by_hex() {
+        assert_eq!(parse_slot("0x82".to_owned()).unwrap(), RetiredSlotId::R1);
+        assert_eq!(parse_slot("0X82".to_owned()).unwrap(), RetiredSlotId::R1);
+        assert!(parse_slot("0x81".to_owned()).is_err());
+        assert!(parse_slot("0x96".to_owned()).is_err());
+    }
+
+    #[test]
+    fn parse_slot_rejects_garbage() {
+        assert!(parse_slot("not a slot".to_owned()).is_err());
+    }
+
+    #[test]
+    fn mgm_key_from_hex_accepts_24_byte_key() {
+        // The factory-default management key; an all-zero key would be rejected as a
+        // weak DES key.
+        assert!(mgm_key_from_hex("010203040506070801020304050607080102030405060708".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn mgm_key_from_hex_rejects_invalid() {
+        assert!(mgm_key_from_hex("not hex".to_owned()).is_err());
+        assert!(mgm_key_from_hex("00".to_owned()).is_err());
+    }
+
+    #[test]
+    fn algorithm_from_string_known_values() {
+        assert!(matches!(
+            algorithm_from_string("p256".to_owned()),
+            Ok(AlgorithmId::EccP256)
+        ));
+        assert!(matches!(
+            algorithm_from_string("p384".to_owned()),
+            Ok(AlgorithmId::EccP384)
+        ));
+        assert!(algorithm_from_string("p521".to_owned()).is_err());
+    }
+
+    #[test]
+    fn ssh_public_key_p256() {
+        let pk_bytes = hex::decode(
+            "036b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+        )
+        .unwrap();
+        let recipient = crate::p256::Recipient::from_bytes(&pk_bytes).unwrap();
+        let ssh_key = ssh_public_key(&Epk::P256(recipient), &[0; 4]).unwrap();
+        assert!(ssh_key.starts_with("ecdsa-sha2-nistp256 "));
+    }
+
+    #[test]
+    fn ssh_public_key_p384_unsupported() {
+        let pk_bytes = hex::decode(
+            "03aa87ca22be8b05378eb1c71ef320ad746e1d3b628ba79b9859f741e082542a385502f25dbf55296c3a545e3872760ab7",
+        )
+        .unwrap();
+        let recipient = crate::p384::Recipient::from_bytes(&pk_bytes).unwrap();
+        assert!(ssh_public_key(&Epk::P384(recipient), &[0; 4]).is_err());
+    }
+
+    #[test]
+    fn policies_from_extension_bytes_decodes_pin_policy() {
+        assert!(matches!(
+            policies_from_extension_bytes(&[0x01, 0x01]).0,
+            Some(PinPolicy::Never)
+        ));
+        assert!(matches!(
+            policies_from_extension_bytes(&[0x02, 0x01]).0,
+            Some(PinPolicy::Once)
+        ));
+        assert!(matches!(
+            policies_from_extension_bytes(&[0x03, 0x01]).0,
+            Some(PinPolicy::Always)
+        ));
+    }
+
+    #[test]
+    fn policies_from_extension_bytes_decodes_touch_policy() {
+        assert!(matches!(
+            policies_from_extension_bytes(&[0x01, 0x01]).1,
+            Some(TouchPolicy::Never)
+        ));
+        assert!(matches!(
+            policies_from_extension_bytes(&[0x01, 0x02]).1,
+            Some(TouchPolicy::Always)
+        ));
+        assert!(matches!(
+            policies_from_extension_bytes(&[0x01, 0x03]).1,
+            Some(TouchPolicy::Cached)
+        ));
+    }
+}
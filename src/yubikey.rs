@@ -7,23 +7,26 @@ use age_core::{
 use age_plugin::identity::{self, Callbacks};
 use bech32::ToBase32;
 use dialoguer::Password;
-use secrecy::ExposeSecret;
+use rand::RngCore;
+use secrecy::{ExposeSecret, SecretVec};
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::io;
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
 use yubikey_piv::{
     certificate::{Certificate, PublicKeyInfo},
-    key::{decrypt_data, AlgorithmId, RetiredSlotId, SlotId},
+    key::{decrypt_data, RetiredSlotId, SlotId},
+    policy::{PinPolicy, TouchPolicy},
     yubikey::Serial,
     MgmKey, Readers, YubiKey,
 };
 
 use crate::{
     error::Error,
-    format::{RecipientLine, STANZA_KEY_LABEL},
-    p256::{Recipient, TAG_BYTES},
-    IDENTITY_PREFIX,
+    format::{Epk, RecipientLine},
+    p256::{self, TAG_BYTES},
+    p384, IDENTITY_PREFIX,
 };
 
 const ONE_SECOND: Duration = Duration::from_secs(1);
@@ -84,20 +87,49 @@ pub(crate) fn open(serial: Option<Serial>) -> Result<YubiKey, Error> {
     Ok(yubikey)
 }
 
-pub(crate) fn manage(yubikey: &mut YubiKey) -> Result<(), Error> {
-    let pin = Password::new()
-        .with_prompt(&format!(
-            "🔤 Enter PIN for YubiKey with serial {} (default is 123456)",
-            yubikey.serial(),
-        ))
-        .interact()?;
-    yubikey.verify_pin(pin.as_bytes())?;
+const DEFAULT_PIN: &str = "123456";
+
+/// Unlocks `yubikey` with a PIN and management key, either supplied non-interactively
+/// (for CI, provisioning scripts, and bulk enrollment) or prompted for interactively
+/// when not supplied.
+///
+/// When `migrate` is set and the YubiKey is still using factory secrets, this also
+/// migrates it off them: if no management key was supplied and the default one
+/// authenticates, a fresh random management key is generated and stored PIN-protected
+/// on the card; if the default PIN was entered interactively, the user is prompted to
+/// replace it. A supplied PIN or management key is never migrated.
+/// Pass `migrate: false` for YubiKeys whose secrets are managed by external tooling.
+pub(crate) fn manage(
+    yubikey: &mut YubiKey,
+    pin: Option<String>,
+    management_key: Option<MgmKey>,
+    migrate: bool,
+) -> Result<(), Error> {
+    let pin_supplied = pin.is_some();
+    let pin = match pin {
+        Some(pin) => pin,
+        None => Password::new()
+            .with_prompt(&format!(
+                "🔤 Enter PIN for YubiKey with serial {} (default is 123456)",
+                yubikey.serial(),
+            ))
+            .interact()?,
+    };
+    yubikey
+        .verify_pin(pin.as_bytes())
+        .map_err(|_| Error::InvalidPin)?;
+
+    if should_migrate_default_pin(migrate, pin_supplied, &pin) {
+        migrate_default_pin(yubikey)?;
+    }
 
-    // TODO: If the user is using the default PIN, change it.
+    if let Some(mgm_key) = management_key {
+        return yubikey
+            .authenticate(mgm_key)
+            .map_err(|_| Error::IncorrectManagementKey);
+    }
 
     // Try to authenticate with the default management key.
-    // TODO: If the YubiKey is using the default management key, migrate it to a
-    // PIN-protected management key.
     let mgm_key = MgmKey::get_protected(yubikey).unwrap_or_default();
     if yubikey.authenticate(mgm_key).is_err() {
         // Management key has been changed; ask the user to provide it.
@@ -120,11 +152,78 @@ pub(crate) fn manage(yubikey: &mut YubiKey) -> Result<(), Error> {
         };
 
         yubikey.authenticate(mgm_key)?;
+    } else if migrate && MgmKey::get_protected(yubikey).is_err() {
+        // We authenticated with the default (non-protected) management key; migrate
+        // to a freshly generated one, stored PIN-protected, so future operations only
+        // need the PIN.
+        let mut key_bytes = [0; 24];
+        rand::rngs::OsRng.fill_bytes(&mut key_bytes);
+        let new_mgm_key = MgmKey::try_from(&key_bytes[..]).expect("key is the correct length");
+
+        MgmKey::set_protected(yubikey, &new_mgm_key)?;
+        yubikey.authenticate(new_mgm_key)?;
+        eprintln!("🔑 Migrated the YubiKey to a PIN-protected management key");
     }
 
     Ok(())
 }
 
+/// Decides whether [`manage`] should prompt to replace the factory-default PIN.
+///
+/// Only prompts when we obtained the PIN interactively; a caller that supplied
+/// `--pin`/`AGE_PLUGIN_YUBIKEY_PIN` has explicitly asked for non-interactive operation
+/// and should not be blocked on a PIN/PUK prompt.
+fn should_migrate_default_pin(migrate: bool, pin_supplied: bool, pin: &str) -> bool {
+    migrate && !pin_supplied && pin == DEFAULT_PIN
+}
+
+/// Prompts the user to replace the factory-default PIN (and PUK) with their own.
+fn migrate_default_pin(yubikey: &mut YubiKey) -> Result<(), Error> {
+    eprintln!("🔤 This YubiKey is using the default PIN; let's set a new one.");
+
+    let new_pin = Password::new()
+        .with_prompt("Enter a new PIN")
+        .with_confirmation("Confirm the new PIN", "PINs did not match")
+        .interact()?;
+    yubikey.change_pin(DEFAULT_PIN.as_bytes(), new_pin.as_bytes())?;
+
+    let new_puk = Password::new()
+        .with_prompt("Enter a new PUK (used to unblock the PIN)")
+        .with_confirmation("Confirm the new PUK", "PUKs did not match")
+        .interact()?;
+    yubikey.change_puk(b"12345678", new_puk.as_bytes())?;
+
+    Ok(())
+}
+
+/// A cache of verified PINs, keyed by YubiKey serial, shared across the stubs being
+/// decrypted in a single session.
+///
+/// A YubiKey with several identities (or a single identity with `PinPolicy::Once`)
+/// only needs its PIN verified once per session; this cache lets later [`Stub::connect`]
+/// calls for the same serial skip the interactive prompt. Cached PINs are wrapped in
+/// `SecretVec` so they are zeroized when the cache (or an evicted entry) is dropped.
+#[derive(Default)]
+pub(crate) struct PinCache(HashMap<Serial, SecretVec<u8>>);
+
+impl PinCache {
+    pub(crate) fn new() -> Self {
+        PinCache(HashMap::new())
+    }
+
+    fn get(&self, serial: Serial) -> Option<Vec<u8>> {
+        self.0.get(&serial).map(|pin| pin.expose_secret().clone())
+    }
+
+    fn remember(&mut self, serial: Serial, pin: Vec<u8>) {
+        self.0.insert(serial, SecretVec::new(pin));
+    }
+
+    fn forget(&mut self, serial: Serial) {
+        self.0.remove(&serial);
+    }
+}
+
 /// A reference to an age key stored in a YubiKey.
 #[derive(Debug)]
 pub struct Stub {
@@ -145,11 +244,14 @@ impl Stub {
     ///
     /// Does not check that the `PublicKey` matches the given `(Serial, SlotId)` tuple;
     /// this is checked at decryption time.
-    pub(crate) fn new(serial: Serial, slot: RetiredSlotId, recipient: &Recipient) -> Self {
+    pub(crate) fn new(serial: Serial, slot: RetiredSlotId, recipient: &Epk) -> Self {
         Stub {
             serial,
             slot,
-            tag: recipient.tag(),
+            tag: match recipient {
+                Epk::P256(pk) => pk.tag(),
+                Epk::P384(pk) => pk.tag(),
+            },
             identity_index: 0,
         }
     }
@@ -187,6 +289,7 @@ impl Stub {
     pub(crate) fn connect(
         &self,
         callbacks: &mut dyn Callbacks,
+        pin_cache: &mut PinCache,
     ) -> io::Result<Result<Connection, identity::Error>> {
         let mut yubikey = match YubiKey::open_by_serial(self.serial) {
             Ok(yk) => yk,
@@ -243,15 +346,32 @@ impl Stub {
             }
         };
 
-        // Read the pubkey from the YubiKey slot and check it still matches.
-        let pk = match Certificate::read(&mut yubikey, SlotId::Retired(self.slot))
-            .ok()
-            .and_then(|cert| match cert.subject_pki() {
-                PublicKeyInfo::EcP256(pubkey) => {
-                    Recipient::from_pubkey(*pubkey).filter(|pk| pk.tag() == self.tag)
-                }
-                _ => None,
-            }) {
+        // Read the pubkey from the YubiKey slot and check it still matches. Prefer
+        // GetMetadata (cheap, no certificate involved) and only fall back to reading
+        // and parsing the slot's certificate on firmware that doesn't support it; in
+        // that case we also don't learn the slot's touch policy, and skip the touch
+        // prompt below (the authenticator-level prompt still appears if one is due).
+        let (pubkey_info, touch_policy, pin_policy) =
+            match crate::util::slot_metadata(&mut yubikey, SlotId::Retired(self.slot)) {
+                Some(metadata) => (metadata.public_key, metadata.touch_policy, metadata.pin_policy),
+                None => (
+                    Certificate::read(&mut yubikey, SlotId::Retired(self.slot))
+                        .ok()
+                        .map(|cert| cert.subject_pki().clone()),
+                    None,
+                    None,
+                ),
+            };
+
+        let pk = match pubkey_info.and_then(|info| match info {
+            PublicKeyInfo::EcP256(pubkey) => p256::Recipient::from_pubkey(pubkey)
+                .map(Epk::P256)
+                .filter(|pk| pk.tag() == self.tag),
+            PublicKeyInfo::EcP384(pubkey) => p384::Recipient::from_pubkey(pubkey)
+                .map(Epk::P384)
+                .filter(|pk| pk.tag() == self.tag),
+            _ => None,
+        }) {
             Some(pk) => pk,
             None => {
                 return Ok(Err(identity::Error::Identity {
@@ -261,69 +381,117 @@ impl Stub {
             }
         };
 
-        let pin = match callbacks.request_secret(&format!(
-            "Enter PIN for YubiKey with serial {}",
-            self.serial
-        ))? {
-            Ok(pin) => pin,
-            Err(_) => {
-                return Ok(Err(identity::Error::Identity {
-                    index: self.identity_index,
-                    message: format!("A PIN is required for YubiKey with serial {}", self.serial),
-                }))
+        // Reuse a PIN already verified earlier in this session, if we have one cached
+        // for this YubiKey. We still re-verify it (silently) rather than trusting the
+        // cache blindly, since the card is the source of truth.
+        //
+        // A slot with `PinPolicy::Always` requires the PIN on every use, so never reuse
+        // a cached PIN for it and always prompt.
+        if matches!(pin_policy, Some(PinPolicy::Always)) {
+            pin_cache.forget(self.serial);
+        }
+        let (pin, from_cache) = match pin_cache.get(self.serial) {
+            Some(pin) => (pin, true),
+            None => {
+                let pin = match callbacks.request_secret(&format!(
+                    "Enter PIN for YubiKey with serial {}",
+                    self.serial
+                ))? {
+                    Ok(pin) => pin,
+                    Err(_) => {
+                        return Ok(Err(identity::Error::Identity {
+                            index: self.identity_index,
+                            message: format!(
+                                "A PIN is required for YubiKey with serial {}",
+                                self.serial
+                            ),
+                        }))
+                    }
+                };
+                (pin.expose_secret().as_bytes().to_vec(), false)
             }
         };
-        if yubikey.verify_pin(pin.expose_secret().as_bytes()).is_err() {
+
+        if yubikey.verify_pin(&pin).is_err() {
+            pin_cache.forget(self.serial);
+
+            // A cached PIN can go stale (e.g. it was changed on another connection);
+            // give the user one more chance to enter it interactively before failing.
+            if from_cache {
+                return self.connect(callbacks, pin_cache);
+            }
+
             return Ok(Err(identity::Error::Identity {
                 index: self.identity_index,
                 message: "Invalid YubiKey PIN".to_owned(),
             }));
         }
 
+        pin_cache.remember(self.serial, pin);
+
         Ok(Ok(Connection {
             yubikey,
             pk,
             slot: self.slot,
             tag: self.tag,
+            touch_policy,
         }))
     }
 }
 
 pub(crate) struct Connection {
     yubikey: YubiKey,
-    pk: Recipient,
+    pk: Epk,
     slot: RetiredSlotId,
     tag: [u8; 4],
+    touch_policy: Option<TouchPolicy>,
+}
+
+/// Whether the ECDH about to run on this slot is expected to block on a physical
+/// touch, and so should be preceded by a touch prompt. We don't know the policy when
+/// the slot's metadata couldn't be read (e.g. older firmware without `GetMetadata`;
+/// the certificate fallback doesn't provide it); in that case we skip our own prompt
+/// and rely on the authenticator-level prompt still appearing if one is due.
+fn touch_prompt_required(touch_policy: Option<TouchPolicy>) -> bool {
+    matches!(touch_policy, Some(TouchPolicy::Always) | Some(TouchPolicy::Cached))
 }
 
 impl Connection {
-    pub(crate) fn unwrap_file_key(&mut self, line: &RecipientLine) -> Result<FileKey, ()> {
+    pub(crate) fn unwrap_file_key(
+        &mut self,
+        line: &RecipientLine,
+        callbacks: &mut dyn Callbacks,
+    ) -> io::Result<Result<FileKey, ()>> {
         assert_eq!(self.tag, line.tag);
 
+        if touch_prompt_required(self.touch_policy) {
+            callbacks.message("👆 Touch your YubiKey to decrypt the file key")?;
+        }
+
         let shared_secret = match decrypt_data(
             &mut self.yubikey,
-            line.epk.decompress().as_bytes(),
-            AlgorithmId::EccP256,
+            &line.epk.decompressed(),
+            line.epk.algorithm_id(),
             SlotId::Retired(self.slot),
         ) {
             Ok(res) => res,
-            Err(_) => return Err(()),
+            Err(_) => return Ok(Err(())),
         };
 
         let mut salt = vec![];
         salt.extend_from_slice(line.epk.as_bytes());
         salt.extend_from_slice(self.pk.as_bytes());
 
-        let enc_key = hkdf(&salt, STANZA_KEY_LABEL, shared_secret.as_ref());
+        let enc_key = hkdf(&salt, line.epk.hkdf_label(), shared_secret.as_ref());
 
         // A failure to decrypt is fatal, because we assume that we won't
         // encounter 32-bit collisions on the key tag embedded in the header.
-        match aead_decrypt(&enc_key, FILE_KEY_BYTES, &line.encrypted_file_key) {
+        Ok(match aead_decrypt(&enc_key, FILE_KEY_BYTES, &line.encrypted_file_key) {
             Ok(pt) => Ok(TryInto::<[u8; FILE_KEY_BYTES]>::try_into(&pt[..])
                 .unwrap()
                 .into()),
             Err(_) => Err(()),
-        }
+        })
     }
 }
 
@@ -331,7 +499,9 @@ impl Connection {
 mod tests {
     use yubikey_piv::{key::RetiredSlotId, Serial};
 
-    use super::Stub;
+    use yubikey_piv::policy::TouchPolicy;
+
+    use super::{should_migrate_default_pin, touch_prompt_required, PinCache, Stub, DEFAULT_PIN};
 
     #[test]
     fn stub_round_trip() {
@@ -345,4 +515,77 @@ mod tests {
         let encoded = stub.to_bytes();
         assert_eq!(Stub::from_bytes(&encoded, 0), Some(stub));
     }
+
+    #[test]
+    fn pin_cache_get_is_empty_until_remembered() {
+        let mut cache = PinCache::new();
+        let serial = Serial::from(42);
+
+        assert_eq!(cache.get(serial), None);
+
+        cache.remember(serial, b"123456".to_vec());
+        assert_eq!(cache.get(serial), Some(b"123456".to_vec()));
+    }
+
+    #[test]
+    fn pin_cache_is_keyed_by_serial() {
+        let mut cache = PinCache::new();
+        let (serial_a, serial_b) = (Serial::from(1), Serial::from(2));
+
+        cache.remember(serial_a, b"111111".to_vec());
+        assert_eq!(cache.get(serial_a), Some(b"111111".to_vec()));
+        assert_eq!(cache.get(serial_b), None);
+    }
+
+    #[test]
+    fn pin_cache_remember_overwrites_existing_entry() {
+        let mut cache = PinCache::new();
+        let serial = Serial::from(42);
+
+        cache.remember(serial, b"111111".to_vec());
+        cache.remember(serial, b"222222".to_vec());
+        assert_eq!(cache.get(serial), Some(b"222222".to_vec()));
+    }
+
+    #[test]
+    fn pin_cache_forget_evicts_the_entry() {
+        let mut cache = PinCache::new();
+        let serial = Serial::from(42);
+
+        cache.remember(serial, b"123456".to_vec());
+        cache.forget(serial);
+        assert_eq!(cache.get(serial), None);
+    }
+
+    #[test]
+    fn should_migrate_default_pin_only_when_interactive_and_default() {
+        assert!(should_migrate_default_pin(true, false, DEFAULT_PIN));
+    }
+
+    #[test]
+    fn should_migrate_default_pin_skips_non_default_pin() {
+        assert!(!should_migrate_default_pin(true, false, "000000"));
+    }
+
+    #[test]
+    fn should_migrate_default_pin_skips_when_supplied_non_interactively() {
+        assert!(!should_migrate_default_pin(true, true, DEFAULT_PIN));
+    }
+
+    #[test]
+    fn should_migrate_default_pin_skips_when_migrate_disabled() {
+        assert!(!should_migrate_default_pin(false, false, DEFAULT_PIN));
+    }
+
+    #[test]
+    fn touch_prompt_required_for_always_and_cached() {
+        assert!(touch_prompt_required(Some(TouchPolicy::Always)));
+        assert!(touch_prompt_required(Some(TouchPolicy::Cached)));
+    }
+
+    #[test]
+    fn touch_prompt_not_required_for_never_or_unknown() {
+        assert!(!touch_prompt_required(Some(TouchPolicy::Never)));
+        assert!(!touch_prompt_required(None));
+    }
 }